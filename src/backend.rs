@@ -0,0 +1,568 @@
+use log::{debug, error, warn};
+use reqwest::blocking::{Client, Response};
+use serde_json::{Value, json};
+use std::io::{BufRead, BufReader};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// A vision-capable chat completion backend: turns a batch of data-URI
+/// encoded images plus a prompt into one response per image. Implementors
+/// own their own HTTP payload shape and retry behavior so the rest of the
+/// tool can stay backend-agnostic. The outer `Result` is for failures that
+/// take out the whole batch (e.g. a request that covers every image at
+/// once); the inner per-image `Result` lets a backend that issues one
+/// request per image report a failure for a single image without
+/// discarding the captions its siblings already got back. `on_delta`, when
+/// set, is called with the accumulated text each time a backend that
+/// supports streaming receives a new chunk; backends without streaming
+/// support simply ignore it.
+pub trait Backend {
+    fn caption(
+        &self,
+        client: &Client,
+        images_b64: &[String],
+        prompt: &str,
+        schema: Option<&Value>,
+        options: Option<&Value>,
+        on_delta: Option<&dyn Fn(&str)>,
+    ) -> Result<Vec<Result<Value, String>>, String>;
+}
+
+/// Cheap, dependency-free jitter source: the low bits of the current time
+/// mixed with the thread id, so concurrent workers don't retry in lockstep.
+fn jitter_millis(max: u64) -> u64 {
+    if max == 0 {
+        return 0;
+    }
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos() as u64)
+        .unwrap_or(0);
+    let tid = format!("{:?}", thread::current().id());
+    let tid_hash = tid
+        .bytes()
+        .fold(0u64, |acc, b| acc.wrapping_mul(31).wrapping_add(b as u64));
+    let mix = nanos.wrapping_add(tid_hash.wrapping_mul(2654435761));
+    mix % (max + 1)
+}
+
+/// Runs `attempt` once, then retries it up to `max_retries` more times with
+/// exponential backoff (capped, with jitter), honoring any retry-after delay the attempt
+/// reports back on a retryable failure.
+fn retry_with_backoff<F>(max_retries: u32, mut attempt: F) -> Result<Value, String>
+where
+    F: FnMut() -> Result<Value, (String, bool, Option<Duration>)>,
+{
+    const BASE_DELAY: Duration = Duration::from_millis(500);
+    const MAX_DELAY: Duration = Duration::from_secs(30);
+
+    let mut attempt_no = 0;
+    loop {
+        match attempt() {
+            Ok(val) => return Ok(val),
+            Err((msg, retryable, retry_after)) => {
+                if !retryable || attempt_no >= max_retries {
+                    return Err(msg);
+                }
+
+                let backoff = BASE_DELAY
+                    .saturating_mul(1 << attempt_no.min(16))
+                    .min(MAX_DELAY);
+                let delay = retry_after
+                    .unwrap_or(backoff)
+                    .saturating_add(Duration::from_millis(jitter_millis(250)));
+
+                warn!(
+                    "Request failed ({}), retrying in {:?} (attempt {}/{})",
+                    msg,
+                    delay,
+                    attempt_no + 1,
+                    max_retries
+                );
+                thread::sleep(delay);
+                attempt_no += 1;
+            }
+        }
+    }
+}
+
+/// Posts `payload` to `url` with the optional bearer `auth` header attached,
+/// and classifies the outcome the way every backend's retry loop expects:
+/// the response on success, or `(message, retryable, retry_after)` on
+/// failure. 429/5xx responses are retryable (carrying any `Retry-After` the
+/// server sent back); other non-2xx responses are fatal. The caller still
+/// owns reading the body, since streaming and non-streaming callers want to
+/// consume it differently.
+fn send_request(
+    client: &Client,
+    url: &str,
+    auth: Option<&str>,
+    payload: &Value,
+) -> Result<Response, (String, bool, Option<Duration>)> {
+    debug!(
+        "Request payload: {}",
+        serde_json::to_string_pretty(payload).unwrap_or_default()
+    );
+
+    let mut req = client.post(url).header("Content-Type", "application/json");
+    if let Some(auth) = auth {
+        req = req.header("Authorization", auth);
+    }
+
+    let resp = req
+        .json(payload)
+        .send()
+        .map_err(|e| (format!("HTTP request failed: {}", e), true, None))?;
+
+    let status = resp.status();
+    let retry_after = resp
+        .headers()
+        .get("Retry-After")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok())
+        .map(Duration::from_secs);
+
+    if status.as_u16() == 429 || status.is_server_error() {
+        let text = resp.text().unwrap_or_default();
+        error!("Server said: {}", text);
+        return Err((format!("HTTP error: {}", status), true, retry_after));
+    }
+    if !status.is_success() {
+        let text = resp.text().unwrap_or_default();
+        error!("Server said: {}", text);
+        return Err((format!("HTTP error: {}", status), false, None));
+    }
+
+    Ok(resp)
+}
+
+/// Reads a `send_request` response to text and parses it as JSON; the
+/// common tail shared by every non-streaming caller.
+fn read_json_response(resp: Response) -> Result<Value, (String, bool, Option<Duration>)> {
+    let text = resp.text().unwrap_or_default();
+    serde_json::from_str(&text).map_err(|e| (format!("Failed to parse JSON: {}", e), false, None))
+}
+
+/// Strips a `data:<mime>;base64,` prefix, if present, down to raw base64.
+/// Ollama's `/api/chat` wants bare base64 in its `images` array.
+fn strip_data_uri_prefix(image: &str) -> &str {
+    image.split(",").last().unwrap_or(image)
+}
+
+/// The existing Ollama `/api/chat` backend: `images` as an array of raw
+/// base64 strings, `format` for the JSON schema. When `stream` is set, the
+/// request asks Ollama for newline-delimited JSON chunks instead of a
+/// single response, purely so progress can be observed (and the partial
+/// text recovered) while the model is still generating; the final written
+/// output is identical either way.
+pub struct OllamaBackend {
+    pub api_url: String,
+    pub model: String,
+    pub api_auth: Option<String>,
+    pub max_retries: u32,
+    pub stream: bool,
+}
+
+/// Applies one NDJSON line from an Ollama `/api/chat` streaming response to
+/// the accumulated caption text, returning whether the stream reported
+/// `"done": true` and the delta (if any) that was appended. Blank lines
+/// (the keep-alive Ollama sends between chunks) are a no-op; a line that
+/// isn't valid JSON is reported as an error since a malformed chunk means
+/// the stream can't be trusted to resume cleanly.
+fn accumulate_stream_line(content: &mut String, line: &str) -> Result<(bool, Option<String>), String> {
+    let trimmed = line.trim();
+    if trimmed.is_empty() {
+        return Ok((false, None));
+    }
+
+    let chunk: Value = serde_json::from_str(trimmed)
+        .map_err(|e| format!("Failed to parse stream chunk: {}", e))?;
+
+    let delta = chunk
+        .get("message")
+        .and_then(|m| m.get("content"))
+        .and_then(|c| c.as_str())
+        .map(|s| s.to_string());
+
+    if let Some(delta) = &delta {
+        content.push_str(delta);
+    }
+
+    let done = chunk.get("done").and_then(|d| d.as_bool()) == Some(true);
+    Ok((done, delta))
+}
+
+impl OllamaBackend {
+    fn caption_streaming(
+        &self,
+        client: &Client,
+        raw_images: &[&str],
+        prompt: &str,
+        schema: Option<&Value>,
+        options: Option<&Value>,
+        on_delta: Option<&dyn Fn(&str)>,
+    ) -> Result<Value, (String, bool, Option<Duration>)> {
+        let messages = vec![json!({
+            "role": "user",
+            "content": prompt,
+            "images": raw_images,
+        })];
+
+        let mut payload = json!({
+            "model": self.model,
+            "messages": messages,
+            "stream": true,
+        });
+
+        if let Some(schema) = schema {
+            payload["format"] = schema.clone();
+        }
+        if let Some(opts) = options {
+            payload["options"] = opts.clone();
+        }
+
+        let resp = send_request(client, &self.api_url, self.api_auth.as_deref(), &payload)?;
+
+        let mut reader = BufReader::new(resp);
+        let mut content = String::new();
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let read = reader
+                .read_line(&mut line)
+                .map_err(|e| (format!("Stream read failed: {}", e), true, None))?;
+            if read == 0 {
+                break;
+            }
+
+            let (done, delta) =
+                accumulate_stream_line(&mut content, &line).map_err(|e| (e, false, None))?;
+
+            if let (true, Some(cb)) = (delta.is_some(), on_delta) {
+                cb(&content);
+            }
+
+            if done {
+                break;
+            }
+        }
+
+        Ok(json!({"message": {"content": content}}))
+    }
+}
+
+impl Backend for OllamaBackend {
+    fn caption(
+        &self,
+        client: &Client,
+        images_b64: &[String],
+        prompt: &str,
+        schema: Option<&Value>,
+        options: Option<&Value>,
+        on_delta: Option<&dyn Fn(&str)>,
+    ) -> Result<Vec<Result<Value, String>>, String> {
+        let raw_images: Vec<&str> = images_b64
+            .iter()
+            .map(|img| strip_data_uri_prefix(img))
+            .collect();
+
+        let resp = retry_with_backoff(self.max_retries, || {
+            if self.stream {
+                return self.caption_streaming(client, &raw_images, prompt, schema, options, on_delta);
+            }
+
+            let messages = vec![json!({
+                "role": "user",
+                "content": prompt,
+                "images": raw_images,
+            })];
+
+            let mut payload = json!({
+                "model": self.model,
+                "messages": messages,
+                "stream": false,
+            });
+
+            if let Some(schema) = schema {
+                payload["format"] = schema.clone();
+            }
+            if let Some(opts) = options {
+                payload["options"] = opts.clone();
+            }
+
+            let resp = send_request(client, &self.api_url, self.api_auth.as_deref(), &payload)?;
+            read_json_response(resp)
+        })?;
+
+        Ok(extract_ollama_contents(&resp).into_iter().map(Ok).collect())
+    }
+}
+
+/// Pulls per-image caption text out of an Ollama `/api/chat` response: a
+/// `messages` array (one caption per input image), a single `message`
+/// (the common one-image case), or, failing both, the raw response so
+/// nothing is silently dropped.
+fn extract_ollama_contents(resp: &Value) -> Vec<Value> {
+    if let Some(messages) = resp.get("messages") {
+        messages
+            .as_array()
+            .map(|arr| {
+                arr.iter()
+                    .map(|msg| msg.get("content").cloned().unwrap_or(json!("")))
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default()
+    } else if let Some(message) = resp.get("message") {
+        vec![message.get("content").cloned().unwrap_or(json!(""))]
+    } else {
+        vec![resp.clone()]
+    }
+}
+
+/// An OpenAI-compatible `/v1/chat/completions` backend. Images are packed
+/// as `image_url` content parts using the data URIs `encode_image_to_base64`
+/// produces, and the JSON schema (if any) is mapped to a `json_schema`
+/// response format. OpenAI-compatible chat completions answer about the
+/// whole message, not per image, so each image in the batch gets its own
+/// request rather than sharing one multi-image completion.
+pub struct OpenAiBackend {
+    pub api_url: String,
+    pub model: String,
+    pub api_auth: Option<String>,
+    pub max_retries: u32,
+}
+
+impl OpenAiBackend {
+    fn complete_one(
+        &self,
+        client: &Client,
+        image_b64: &str,
+        prompt: &str,
+        schema: Option<&Value>,
+        options: Option<&Value>,
+    ) -> Result<Value, String> {
+        let resp = retry_with_backoff(self.max_retries, || {
+            let payload = build_openai_payload(&self.model, image_b64, prompt, schema, options);
+            let resp = send_request(client, &self.api_url, self.api_auth.as_deref(), &payload)?;
+            read_json_response(resp)
+        })?;
+
+        Ok(extract_openai_content(&resp))
+    }
+}
+
+impl Backend for OpenAiBackend {
+    fn caption(
+        &self,
+        client: &Client,
+        images_b64: &[String],
+        prompt: &str,
+        schema: Option<&Value>,
+        options: Option<&Value>,
+        _on_delta: Option<&dyn Fn(&str)>,
+    ) -> Result<Vec<Result<Value, String>>, String> {
+        Ok(images_b64
+            .iter()
+            .map(|image| self.complete_one(client, image, prompt, schema, options))
+            .collect())
+    }
+}
+
+/// Builds the `/v1/chat/completions` request body for one image: the
+/// prompt and image packed as content parts, with the JSON schema (if any)
+/// mapped to a `json_schema` response format and `options` merged in
+/// verbatim as top-level request fields (e.g. `temperature`).
+fn build_openai_payload(
+    model: &str,
+    image_b64: &str,
+    prompt: &str,
+    schema: Option<&Value>,
+    options: Option<&Value>,
+) -> Value {
+    let content_parts = vec![
+        json!({"type": "text", "text": prompt}),
+        json!({"type": "image_url", "image_url": {"url": image_b64}}),
+    ];
+
+    let mut payload = json!({
+        "model": model,
+        "messages": [{
+            "role": "user",
+            "content": content_parts,
+        }],
+    });
+
+    if let Some(schema) = schema {
+        payload["response_format"] = json!({
+            "type": "json_schema",
+            "json_schema": {
+                "name": "response",
+                "schema": schema,
+                "strict": true,
+            },
+        });
+    }
+    if let Some(Value::Object(opts)) = options {
+        for (k, v) in opts {
+            payload[k] = v.clone();
+        }
+    }
+
+    payload
+}
+
+/// Pulls the caption text out of a `/v1/chat/completions` response:
+/// `choices[0].message.content`, defaulting to an empty string if the
+/// shape doesn't match (e.g. the request was refused with no completion).
+fn extract_openai_content(resp: &Value) -> Value {
+    resp.get("choices")
+        .and_then(|c| c.as_array())
+        .and_then(|arr| arr.first())
+        .and_then(|choice| choice.get("message"))
+        .and_then(|message| message.get("content"))
+        .cloned()
+        .unwrap_or(json!(""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jitter_millis_zero_max_is_zero() {
+        assert_eq!(jitter_millis(0), 0);
+    }
+
+    #[test]
+    fn jitter_millis_stays_within_bound() {
+        for _ in 0..20 {
+            assert!(jitter_millis(250) <= 250);
+        }
+    }
+
+    #[test]
+    fn retry_with_backoff_returns_ok_on_first_success() {
+        let mut calls = 0;
+        let result = retry_with_backoff(3, || {
+            calls += 1;
+            Ok(json!({"ok": true}))
+        });
+        assert_eq!(result, Ok(json!({"ok": true})));
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn retry_with_backoff_stops_immediately_on_non_retryable_error() {
+        let mut calls = 0;
+        let result = retry_with_backoff(5, || {
+            calls += 1;
+            Err(("bad request".to_string(), false, None))
+        });
+        assert_eq!(result, Err("bad request".to_string()));
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn retry_with_backoff_gives_up_after_max_retries() {
+        let mut calls = 0;
+        let result = retry_with_backoff(0, || {
+            calls += 1;
+            Err(("server error".to_string(), true, None))
+        });
+        assert_eq!(result, Err("server error".to_string()));
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn build_openai_payload_omits_response_format_without_schema() {
+        let payload = build_openai_payload("gpt-4o", "data:image/png;base64,AA==", "describe", None, None);
+        assert_eq!(payload["model"], json!("gpt-4o"));
+        assert_eq!(payload.get("response_format"), None);
+        assert_eq!(
+            payload["messages"][0]["content"][1]["image_url"]["url"],
+            json!("data:image/png;base64,AA==")
+        );
+    }
+
+    #[test]
+    fn build_openai_payload_maps_schema_to_strict_json_schema_format() {
+        let schema = json!({"type": "object", "properties": {"caption": {"type": "string"}}});
+        let payload = build_openai_payload("gpt-4o", "data:image/png;base64,AA==", "describe", Some(&schema), None);
+        assert_eq!(payload["response_format"]["type"], json!("json_schema"));
+        assert_eq!(payload["response_format"]["json_schema"]["strict"], json!(true));
+        assert_eq!(payload["response_format"]["json_schema"]["schema"], schema);
+    }
+
+    #[test]
+    fn build_openai_payload_merges_options_as_top_level_fields() {
+        let options = json!({"temperature": 0.2});
+        let payload = build_openai_payload("gpt-4o", "data:image/png;base64,AA==", "describe", None, Some(&options));
+        assert_eq!(payload["temperature"], json!(0.2));
+    }
+
+    #[test]
+    fn extract_openai_content_reads_first_choice_message() {
+        let resp = json!({"choices": [{"message": {"content": "a cat"}}]});
+        assert_eq!(extract_openai_content(&resp), json!("a cat"));
+    }
+
+    #[test]
+    fn extract_openai_content_defaults_to_empty_string_on_unexpected_shape() {
+        assert_eq!(extract_openai_content(&json!({})), json!(""));
+        assert_eq!(extract_openai_content(&json!({"choices": []})), json!(""));
+    }
+
+    #[test]
+    fn extract_ollama_contents_reads_messages_array() {
+        let resp = json!({"messages": [{"content": "a"}, {"content": "b"}]});
+        assert_eq!(extract_ollama_contents(&resp), vec![json!("a"), json!("b")]);
+    }
+
+    #[test]
+    fn extract_ollama_contents_reads_single_message() {
+        let resp = json!({"message": {"content": "a dog"}});
+        assert_eq!(extract_ollama_contents(&resp), vec![json!("a dog")]);
+    }
+
+    #[test]
+    fn extract_ollama_contents_falls_back_to_raw_response() {
+        let resp = json!({"unexpected": "shape"});
+        assert_eq!(extract_ollama_contents(&resp), vec![resp.clone()]);
+    }
+
+    #[test]
+    fn accumulate_stream_line_appends_delta_and_reports_not_done() {
+        let mut content = String::new();
+        let (done, delta) =
+            accumulate_stream_line(&mut content, "{\"message\": {\"content\": \"a \"}, \"done\": false}\n")
+                .unwrap();
+        assert!(!done);
+        assert_eq!(delta, Some("a ".to_string()));
+        assert_eq!(content, "a ");
+    }
+
+    #[test]
+    fn accumulate_stream_line_ignores_blank_lines() {
+        let mut content = String::new();
+        let (done, delta) = accumulate_stream_line(&mut content, "\n").unwrap();
+        assert!(!done);
+        assert_eq!(delta, None);
+        assert_eq!(content, "");
+    }
+
+    #[test]
+    fn accumulate_stream_line_errors_on_malformed_json() {
+        let mut content = String::new();
+        assert!(accumulate_stream_line(&mut content, "not json\n").is_err());
+    }
+
+    #[test]
+    fn accumulate_stream_line_handles_final_done_without_content() {
+        let mut content = String::new();
+        accumulate_stream_line(&mut content, "{\"message\": {\"content\": \"cat\"}}\n").unwrap();
+        let (done, delta) = accumulate_stream_line(&mut content, "{\"done\": true}\n").unwrap();
+        assert!(done);
+        assert_eq!(delta, None);
+        assert_eq!(content, "cat");
+    }
+}
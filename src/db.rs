@@ -0,0 +1,271 @@
+use rusqlite::{Connection, params};
+use serde_json::Value;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Consolidated SQLite output mode: one `captions` row per image instead
+/// of one JSON file per image, so large datasets stay queryable. When a
+/// JSON schema is in use, its top-level scalar properties are promoted
+/// into their own typed columns alongside the raw JSON payload.
+pub struct OutputDb {
+    conn: Mutex<Connection>,
+    schema_columns: Vec<(String, &'static str)>,
+}
+
+/// Quotes a SQL identifier, escaping embedded `"` characters so a schema
+/// property name can't break out of the quoted identifier.
+fn quote_ident(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// Maps a JSON-schema property type to a SQLite column type, skipping
+/// object/array properties (those stay nested inside `payload` only).
+fn sqlite_type_for(schema_type: &str) -> Option<&'static str> {
+    match schema_type {
+        "string" => Some("TEXT"),
+        "integer" => Some("INTEGER"),
+        "number" => Some("REAL"),
+        "boolean" => Some("INTEGER"),
+        _ => None,
+    }
+}
+
+/// Fixed columns every `captions` row has regardless of schema. A schema
+/// property sharing one of these names would collide with it in the
+/// generated column list, so such properties are skipped for promotion
+/// (they're still available in the raw `payload` JSON).
+const RESERVED_COLUMNS: [&str; 5] = ["filename", "prompt", "model", "created_at", "payload"];
+
+/// Extracts `(column_name, sqlite_type)` pairs for every scalar top-level
+/// property of a JSON schema's `properties` map, skipping any property
+/// whose name collides with a fixed `captions` column.
+fn scalar_columns(schema_obj: &Value) -> Vec<(String, &'static str)> {
+    schema_obj
+        .get("properties")
+        .and_then(|p| p.as_object())
+        .map(|props| {
+            props
+                .iter()
+                .filter(|(name, _)| !RESERVED_COLUMNS.contains(&name.as_str()))
+                .filter_map(|(name, prop)| {
+                    let schema_type = prop.get("type").and_then(|t| t.as_str())?;
+                    sqlite_type_for(schema_type).map(|col_type| (name.clone(), col_type))
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+impl OutputDb {
+    pub fn open(path: &str, schema_obj: Option<&Value>) -> Result<Self, String> {
+        let conn = Connection::open(path).map_err(|e| format!("Failed to open {}: {}", path, e))?;
+
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS captions (
+                filename TEXT PRIMARY KEY,
+                prompt TEXT NOT NULL,
+                model TEXT NOT NULL,
+                created_at TEXT NOT NULL,
+                payload TEXT NOT NULL
+            )",
+            [],
+        )
+        .map_err(|e| format!("Failed to create captions table: {}", e))?;
+
+        let schema_columns = schema_obj.map(scalar_columns).unwrap_or_default();
+
+        let mut existing: std::collections::HashSet<String> = std::collections::HashSet::new();
+        {
+            let mut stmt = conn
+                .prepare("PRAGMA table_info(captions)")
+                .map_err(|e| format!("Failed to inspect captions table: {}", e))?;
+            let cols = stmt
+                .query_map([], |row| row.get::<_, String>(1))
+                .map_err(|e| format!("Failed to inspect captions table: {}", e))?;
+            for col in cols {
+                existing.insert(col.map_err(|e| e.to_string())?);
+            }
+        }
+
+        for (name, col_type) in &schema_columns {
+            if !existing.contains(name) {
+                conn.execute(
+                    &format!(
+                        "ALTER TABLE captions ADD COLUMN {} {}",
+                        quote_ident(name),
+                        col_type
+                    ),
+                    [],
+                )
+                .map_err(|e| format!("Failed to add column {}: {}", name, e))?;
+            }
+        }
+
+        Ok(OutputDb {
+            conn: Mutex::new(conn),
+            schema_columns,
+        })
+    }
+
+    pub fn exists(&self, filename: &str) -> Result<bool, String> {
+        let conn = self.conn.lock().unwrap();
+        conn.query_row(
+            "SELECT 1 FROM captions WHERE filename = ?1",
+            params![filename],
+            |_| Ok(()),
+        )
+        .map(|_| true)
+        .or_else(|e| match e {
+            rusqlite::Error::QueryReturnedNoRows => Ok(false),
+            e => Err(format!("Failed to query captions table: {}", e)),
+        })
+    }
+
+    /// Inserts one row per `(filename, payload)` pair in a single
+    /// transaction, so a crash mid-batch leaves the database consistent.
+    pub fn insert_batch(
+        &self,
+        rows: &[(String, Value)],
+        prompt: &str,
+        model: &str,
+    ) -> Result<(), String> {
+        let created_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs().to_string())
+            .unwrap_or_default();
+
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn
+            .transaction()
+            .map_err(|e| format!("Failed to start transaction: {}", e))?;
+
+        for (filename, payload) in rows {
+            let payload_str =
+                serde_json::to_string(payload).map_err(|e| format!("Failed to serialize payload: {}", e))?;
+
+            let mut columns = vec!["filename", "prompt", "model", "created_at", "payload"];
+            let mut values: Vec<Box<dyn rusqlite::ToSql>> = vec![
+                Box::new(filename.clone()),
+                Box::new(prompt.to_string()),
+                Box::new(model.to_string()),
+                Box::new(created_at.clone()),
+                Box::new(payload_str),
+            ];
+
+            for (name, _) in &self.schema_columns {
+                if let Some(scalar) = payload.get(name) {
+                    columns.push(name.as_str());
+                    values.push(scalar_to_sql(scalar));
+                }
+            }
+
+            let placeholders: Vec<String> = (1..=columns.len()).map(|i| format!("?{}", i)).collect();
+            let quoted_columns: Vec<String> = columns.iter().map(|c| quote_ident(c)).collect();
+            let sql = format!(
+                "INSERT OR REPLACE INTO captions ({}) VALUES ({})",
+                quoted_columns.join(", "),
+                placeholders.join(", ")
+            );
+
+            let params: Vec<&dyn rusqlite::ToSql> = values.iter().map(|v| v.as_ref()).collect();
+            tx.execute(&sql, params.as_slice())
+                .map_err(|e| format!("Failed to insert row for {}: {}", filename, e))?;
+        }
+
+        tx.commit()
+            .map_err(|e| format!("Failed to commit transaction: {}", e))
+    }
+}
+
+/// Converts a scalar JSON value into a boxed `ToSql`, falling back to its
+/// string form for anything that doesn't map cleanly onto a SQLite type.
+fn scalar_to_sql(value: &Value) -> Box<dyn rusqlite::ToSql> {
+    match value {
+        Value::Null => Box::new(rusqlite::types::Null),
+        Value::String(s) => Box::new(s.clone()),
+        Value::Bool(b) => Box::new(*b),
+        Value::Number(n) => {
+            if let Some(i) = n.as_i64() {
+                Box::new(i)
+            } else {
+                Box::new(n.as_f64().unwrap_or(0.0))
+            }
+        }
+        other => Box::new(other.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn sqlite_type_for_maps_known_scalar_types() {
+        assert_eq!(sqlite_type_for("string"), Some("TEXT"));
+        assert_eq!(sqlite_type_for("integer"), Some("INTEGER"));
+        assert_eq!(sqlite_type_for("number"), Some("REAL"));
+        assert_eq!(sqlite_type_for("boolean"), Some("INTEGER"));
+    }
+
+    #[test]
+    fn sqlite_type_for_skips_nested_types() {
+        assert_eq!(sqlite_type_for("object"), None);
+        assert_eq!(sqlite_type_for("array"), None);
+    }
+
+    #[test]
+    fn scalar_columns_promotes_only_scalar_properties() {
+        let schema = json!({
+            "properties": {
+                "caption": {"type": "string"},
+                "confidence": {"type": "number"},
+                "tags": {"type": "array"},
+                "metadata": {"type": "object"}
+            }
+        });
+        let mut columns = scalar_columns(&schema);
+        columns.sort();
+        assert_eq!(
+            columns,
+            vec![
+                ("caption".to_string(), "TEXT"),
+                ("confidence".to_string(), "REAL")
+            ]
+        );
+    }
+
+    #[test]
+    fn scalar_columns_handles_missing_properties() {
+        assert_eq!(scalar_columns(&json!({})), Vec::new());
+    }
+
+    #[test]
+    fn scalar_columns_skips_names_that_collide_with_fixed_columns() {
+        let schema = json!({
+            "properties": {
+                "filename": {"type": "string"},
+                "model": {"type": "string"},
+                "caption": {"type": "string"}
+            }
+        });
+        assert_eq!(
+            scalar_columns(&schema),
+            vec![("caption".to_string(), "TEXT")]
+        );
+    }
+
+    #[test]
+    fn quote_ident_escapes_embedded_quotes() {
+        assert_eq!(quote_ident("caption"), "\"caption\"");
+        assert_eq!(quote_ident("foo\"bar"), "\"foo\"\"bar\"");
+    }
+
+    #[test]
+    fn scalar_to_sql_maps_null_to_sql_null() {
+        use rusqlite::types::{ToSqlOutput, Value as SqlValue};
+        let boxed = scalar_to_sql(&Value::Null);
+        let output = boxed.to_sql().unwrap();
+        assert_eq!(output, ToSqlOutput::Owned(SqlValue::Null));
+    }
+}
@@ -1,13 +1,21 @@
+mod backend;
+mod db;
+
+use backend::{Backend, OllamaBackend, OpenAiBackend};
 use base64::{Engine as _, engine::general_purpose};
 use clap::{Arg, Command};
-use indicatif::{ProgressBar, ProgressStyle};
-use log::{debug, error, warn};
+use db::OutputDb;
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use log::{error, warn};
 use reqwest::blocking::Client;
 use serde_json::{Value, json};
 use std::collections::HashSet;
 use std::fs::{self, File};
 use std::io::{Read, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
 
 fn encode_image_to_base64(path: &Path) -> Result<(String, String), String> {
     let mut file = File::open(path).map_err(|e| format!("Failed to open file: {}", e))?;
@@ -33,54 +41,208 @@ fn encode_image_to_base64(path: &Path) -> Result<(String, String), String> {
     Ok((b64_data, mime_type.to_string()))
 }
 
-fn call_ollama_structured(
+/// Resolves the bearer credential to send to Ollama, preferring the
+/// `--api-auth` flag and falling back to the `OLLAMA_API_AUTH` environment
+/// variable. A bare token (no scheme) is wrapped as `Bearer <token>` so
+/// users can pass either a raw header value or just the secret.
+fn resolve_api_auth(cli_value: Option<&String>) -> Option<String> {
+    let raw = cli_value
+        .cloned()
+        .or_else(|| std::env::var("OLLAMA_API_AUTH").ok())?;
+
+    if raw.trim().is_empty() {
+        return None;
+    }
+
+    if raw.contains(' ') {
+        Some(raw)
+    } else {
+        Some(format!("Bearer {}", raw))
+    }
+}
+
+/// Read-only settings shared by every worker in the pool.
+struct JobConfig {
+    backend: Box<dyn Backend + Send + Sync>,
+    model: String,
+    prompt: String,
+    output_dir: String,
+    pretty_json: bool,
+    file_suffix: String,
+    schema_obj: Option<Value>,
+    options: Option<Value>,
+    max_consecutive_failures: usize,
+    output_db: Option<Arc<OutputDb>>,
+    stream: bool,
+}
+
+/// Encodes, captions and writes the outputs for a single batch of files.
+/// Shared with every worker in the concurrency pool so batches can be
+/// processed independently of one another and of completion order. Tracks
+/// consecutive batch failures across the whole run and signals `abort`
+/// once `max_consecutive_failures` is reached.
+fn process_batch(
     client: &Client,
-    api_url: &str,
-    model: &str,
-    images_b64: &[String],
-    prompt: &str,
-    schema_obj: Option<&Value>,
-    options: Option<&Value>,
-) -> Result<Value, String> {
-    let messages = vec![json!({        "role": "user",
-        "content": prompt,
-        "images": images_b64,
-    })];
-
-    let mut payload = json!({
-        "model": model,
-        "messages": messages,
-        "stream": false,
-    });
+    config: &JobConfig,
+    batch: &[PathBuf],
+    pb: &ProgressBar,
+    multi: &MultiProgress,
+    consecutive_failures: &AtomicUsize,
+    abort: &AtomicBool,
+) {
+    let mut images_b64 = Vec::new();
+    let mut batch_names = Vec::new();
 
-    if let Some(schema) = schema_obj {
-        payload["format"] = schema.clone();
+    for path in batch {
+        match encode_image_to_base64(path) {
+            Ok((b64, mime)) => {
+                images_b64.push(format!("data:{};base64,{}", mime, b64));
+                batch_names.push(path.file_stem().unwrap().to_string_lossy().to_string());
+            }
+            Err(e) => {
+                error!("Error encoding {}: {}", path.display(), e);
+                pb.inc(1);
+            }
+        }
     }
-    if let Some(opts) = options {
-        payload["options"] = opts.clone();
+
+    if images_b64.is_empty() {
+        return;
     }
 
-    debug!(
-        "Request payload: {}",
-        serde_json::to_string_pretty(&payload).unwrap_or_default()
-    );
+    let spinner = config.stream.then(|| {
+        let spinner = multi.add(ProgressBar::new_spinner());
+        spinner.set_style(
+            ProgressStyle::default_spinner()
+                .template("{spinner} {msg}")
+                .unwrap_or_else(|_| ProgressStyle::default_spinner()),
+        );
+        spinner
+    });
+    let on_delta = spinner.as_ref().map(|spinner| {
+        let cb: Box<dyn Fn(&str)> = Box::new(move |partial: &str| {
+            let preview: String = partial.chars().rev().take(60).collect::<String>();
+            let preview: String = preview.chars().rev().collect();
+            spinner.set_message(preview);
+            spinner.tick();
+        });
+        cb
+    });
 
-    let resp = client
-        .post(api_url)
-        .header("Content-Type", "application/json")
-        .json(&payload)
-        .send()
-        .map_err(|e| format!("HTTP request failed: {}", e))?;
+    let result = config.backend.caption(
+        client,
+        &images_b64,
+        &config.prompt,
+        config.schema_obj.as_ref(),
+        config.options.as_ref(),
+        on_delta.as_deref(),
+    );
 
-    let status = resp.status();
-    let text = resp.text().unwrap_or_default();
+    if let Some(spinner) = &spinner {
+        spinner.finish_and_clear();
+    }
 
-    if !status.is_success() {
-        error!("Server said: {}", text);
-        return Err(format!("HTTP error: {}", status));
+    // A batch in which every image failed counts as a batch failure too.
+    let batch_failed = match &result {
+        Ok(contents) => contents.iter().all(|c| c.is_err()),
+        Err(_) => true,
+    };
+    if batch_failed {
+        let failures = consecutive_failures.fetch_add(1, Ordering::SeqCst) + 1;
+        if failures >= config.max_consecutive_failures {
+            error!(
+                "Reached {} consecutive batch failures; aborting run",
+                failures
+            );
+            abort.store(true, Ordering::SeqCst);
+        }
+    } else {
+        consecutive_failures.store(0, Ordering::SeqCst);
     }
 
-    serde_json::from_str(&text).map_err(|e| format!("Failed to parse JSON: {}", e))
+    match result {
+        Ok(contents) => {
+            if contents.len() != batch_names.len() {
+                warn!(
+                    "Backend returned {} result(s) for a batch of {}; matching by position",
+                    contents.len(),
+                    batch_names.len()
+                );
+            }
+
+            let mut db_rows = Vec::new();
+
+            for (name, content) in batch_names.iter().zip(contents.iter()) {
+                let content = match content {
+                    Ok(content) => content,
+                    Err(e) => {
+                        error!("Error captioning {}: {}", name, e);
+                        continue;
+                    }
+                };
+
+                let output_data = if config.schema_obj.is_some() {
+                    match serde_json::from_value::<Value>(content.clone()) {
+                        Ok(val) => val,
+                        Err(_) => {
+                            warn!("Response for {} is not valid JSON. Storing raw text.", name);
+                            json!(content)
+                        }
+                    }
+                } else {
+                    content.clone()
+                };
+
+                let json_val = output_data
+                    .as_str()
+                    .and_then(|s| serde_json::from_str(s).ok())
+                    .unwrap_or_else(|| output_data.clone());
+
+                if config.output_db.is_some() {
+                    db_rows.push((name.clone(), json_val));
+                    continue;
+                }
+
+                let out_fname = Path::new(&config.output_dir)
+                    .join(format!("{}{}.json", name, config.file_suffix));
+                let mut fo = match File::create(&out_fname) {
+                    Ok(fo) => fo,
+                    Err(e) => {
+                        error!("Failed to create output file {}: {}", out_fname.display(), e);
+                        continue;
+                    }
+                };
+
+                let json_str = if config.pretty_json {
+                    serde_json::to_string_pretty(&json_val)
+                } else {
+                    serde_json::to_string(&json_val)
+                };
+                let json_str = match json_str {
+                    Ok(s) => s,
+                    Err(e) => {
+                        error!("Failed to serialize output for {}: {}", name, e);
+                        continue;
+                    }
+                };
+                if let Err(e) = fo.write_all(json_str.as_bytes()) {
+                    error!("Failed to write output file {}: {}", out_fname.display(), e);
+                }
+            }
+
+            if let Some(db) = &config.output_db {
+                if !db_rows.is_empty() {
+                    if let Err(e) = db.insert_batch(&db_rows, &config.prompt, &config.model) {
+                        error!("Failed to write batch to output DB: {}", e);
+                    }
+                }
+            }
+        }
+        Err(e) => {
+            error!("Error processing batch: {}", e);
+        }
+    }
+    pb.inc(batch.len() as u64);
 }
 
 fn main() {
@@ -154,6 +316,47 @@ fn main() {
                 .default_value("")
                 .help("Suffix to append to JSON file names."),
         )
+        .arg(
+            Arg::new("api_auth")
+                .long("api-auth")
+                .help("Authorization header value for the Ollama API, e.g. 'Bearer xyz' or a bare token (falls back to OLLAMA_API_AUTH)"),
+        )
+        .arg(
+            Arg::new("concurrency")
+                .long("concurrency")
+                .default_value("1")
+                .help("Number of batches to process in parallel"),
+        )
+        .arg(
+            Arg::new("max_retries")
+                .long("max-retries")
+                .default_value("5")
+                .help("Max retries per request on connection errors, 429s or 5xx responses"),
+        )
+        .arg(
+            Arg::new("max_consecutive_failures")
+                .long("max-consecutive-failures")
+                .default_value("10")
+                .help("Abort the run after this many consecutive batch failures"),
+        )
+        .arg(
+            Arg::new("backend")
+                .long("backend")
+                .value_parser(["ollama", "openai"])
+                .default_value("ollama")
+                .help("Inference backend to target"),
+        )
+        .arg(
+            Arg::new("output_db")
+                .long("output-db")
+                .help("Write results into a SQLite database at this path instead of per-file JSON"),
+        )
+        .arg(
+            Arg::new("stream")
+                .long("stream")
+                .action(clap::ArgAction::SetTrue)
+                .help("Stream responses from the Ollama backend for incremental progress (output is unchanged)"),
+        )
         .get_matches();
 
     let input_dir = matches.get_one::<String>("dir").unwrap();
@@ -173,10 +376,39 @@ fn main() {
 
     let skip_existing = matches.get_flag("skip_existing");
     let file_suffix = matches.get_one::<String>("suffix").unwrap();
+    let api_auth = resolve_api_auth(matches.get_one::<String>("api_auth"));
+    let concurrency: usize = matches
+        .get_one::<String>("concurrency")
+        .unwrap()
+        .parse()
+        .unwrap_or(1)
+        .max(1);
+    let max_retries: u32 = matches
+        .get_one::<String>("max_retries")
+        .unwrap()
+        .parse()
+        .unwrap_or(5);
+    let max_consecutive_failures: usize = matches
+        .get_one::<String>("max_consecutive_failures")
+        .unwrap()
+        .parse()
+        .unwrap_or(10)
+        .max(1);
+    let backend_name = matches.get_one::<String>("backend").unwrap().as_str();
+    let stream = matches.get_flag("stream");
+    if stream && backend_name != "ollama" {
+        warn!("--stream is only supported by the ollama backend; ignoring");
+    }
 
     let schema_obj = matches.get_one::<String>("schema").map(|schema_path| {
-        let schema_str = fs::read_to_string(schema_path).expect("Failed to read schema file");
-        serde_json::from_str(&schema_str).expect("Invalid JSON schema")
+        let schema_str = fs::read_to_string(schema_path).unwrap_or_else(|e| {
+            error!("Failed to read schema file {}: {}", schema_path, e);
+            std::process::exit(1);
+        });
+        serde_json::from_str(&schema_str).unwrap_or_else(|e| {
+            error!("Invalid JSON schema in {}: {}", schema_path, e);
+            std::process::exit(1);
+        })
     });
 
     let options = matches.get_one::<String>("options").map(|opts| {
@@ -186,7 +418,16 @@ fn main() {
         })
     });
 
-    fs::create_dir_all(output_dir).expect("Failed to create output directory");
+    let output_db = matches.get_one::<String>("output_db").map(|db_path| {
+        Arc::new(OutputDb::open(db_path, schema_obj.as_ref()).unwrap_or_else(|e| {
+            error!("Failed to open output DB {}: {}", db_path, e);
+            std::process::exit(1);
+        }))
+    });
+
+    if output_db.is_none() {
+        fs::create_dir_all(output_dir).expect("Failed to create output directory");
+    }
 
     let supported_ext: HashSet<&str> = ["jpg", "jpeg", "png", "bmp", "gif", "webp"]
         .iter()
@@ -201,9 +442,18 @@ fn main() {
 
             if supported_ext.contains(ext.as_str()) {
                 if skip_existing {
-                    let json_path = Path::new(output_dir)
-                        .join(format!("{}{}.json", path.file_stem().unwrap().to_string_lossy().to_string(), file_suffix));
-                    if json_path.exists() {
+                    let stem = path.file_stem().unwrap().to_string_lossy().to_string();
+                    let already_done = if let Some(db) = &output_db {
+                        db.exists(&stem).unwrap_or_else(|e| {
+                            error!("Failed to query output DB for {}: {}", stem, e);
+                            false
+                        })
+                    } else {
+                        Path::new(output_dir)
+                            .join(format!("{}{}.json", stem, file_suffix))
+                            .exists()
+                    };
+                    if already_done {
                         return None;
                     } else {
                         return Some(path);
@@ -217,98 +467,116 @@ fn main() {
         })
         .collect();
 
-    let pb = ProgressBar::new(files.len() as u64);
+    let multi = MultiProgress::new();
+    let pb = multi.add(ProgressBar::new(files.len() as u64));
     pb.set_style(
         ProgressStyle::default_bar()
             .template("{wide_bar} {pos}/{len}")
             .expect("Error creating progress bar"),
     );
 
-    let client = Client::new();
+    let backend: Box<dyn Backend + Send + Sync> = match backend_name {
+        "openai" => Box::new(OpenAiBackend {
+            api_url: api_url.clone(),
+            model: model.clone(),
+            api_auth,
+            max_retries,
+        }),
+        _ => Box::new(OllamaBackend {
+            api_url: api_url.clone(),
+            model: model.clone(),
+            api_auth,
+            max_retries,
+            stream,
+        }),
+    };
 
-    for batch in files.chunks(batch_size) {
-        let mut images_b64 = Vec::new();
-        let mut batch_names = Vec::new();
+    let config = Arc::new(JobConfig {
+        backend,
+        model: model.clone(),
+        prompt: prompt.clone(),
+        output_dir: output_dir.to_string(),
+        pretty_json,
+        file_suffix: file_suffix.clone(),
+        schema_obj,
+        options,
+        max_consecutive_failures,
+        output_db,
+        stream: stream && backend_name == "ollama",
+    });
 
-        for path in batch {
-            match encode_image_to_base64(path) {
-                Ok((b64, _mime)) => {
-                    images_b64.push(b64);
-                    batch_names.push(path.file_stem().unwrap().to_string_lossy().to_string());
-                }
-                Err(e) => {
-                    error!("Error encoding {}: {}", path.display(), e);
-                    pb.inc(1);
+    let batches: Vec<Vec<PathBuf>> = files
+        .chunks(batch_size)
+        .map(|batch| batch.to_vec())
+        .collect();
+    let work = Arc::new(Mutex::new(batches.into_iter()));
+    let consecutive_failures = Arc::new(AtomicUsize::new(0));
+    let abort = Arc::new(AtomicBool::new(false));
+
+    let workers = concurrency.min(files.len().max(1));
+    let mut handles = Vec::with_capacity(workers);
+    for _ in 0..workers {
+        let work = Arc::clone(&work);
+        let config = Arc::clone(&config);
+        let pb = pb.clone();
+        let multi = multi.clone();
+        let consecutive_failures = Arc::clone(&consecutive_failures);
+        let abort = Arc::clone(&abort);
+        handles.push(thread::spawn(move || {
+            let client = Client::new();
+            loop {
+                if abort.load(Ordering::SeqCst) {
+                    break;
                 }
+                let batch = match work.lock().unwrap().next() {
+                    Some(batch) => batch,
+                    None => break,
+                };
+                process_batch(
+                    &client,
+                    &config,
+                    &batch,
+                    &pb,
+                    &multi,
+                    &consecutive_failures,
+                    &abort,
+                );
             }
-        }
+        }));
+    }
 
-        if images_b64.is_empty() {
-            continue;
-        }
+    for handle in handles {
+        handle.join().expect("Worker thread panicked");
+    }
 
-        match call_ollama_structured(
-            &client,
-            api_url,
-            model,
-            &images_b64,
-            prompt,
-            schema_obj.as_ref(),
-            options.as_ref(),
-        ) {
-            Ok(resp) => {
-                let contents = if let Some(messages) = resp.get("messages") {
-                    messages
-                        .as_array()
-                        .map(|arr| {
-                            arr.iter()
-                                .map(|msg| msg.get("content").cloned().unwrap_or(json!("")))
-                                .collect::<Vec<_>>()
-                        })
-                        .unwrap_or_default()
-                } else if let Some(message) = resp.get("message") {
-                    vec![message.get("content").cloned().unwrap_or(json!(""))]
-                } else {
-                    vec![resp.clone()]
-                };
+    pb.finish_with_message("Done");
+}
 
-                for (i, content) in contents.iter().enumerate() {
-                    let output_data = if schema_obj.is_some() {
-                        match serde_json::from_value::<Value>(content.clone()) {
-                            Ok(val) => val,
-                            Err(_) => {
-                                warn!(
-                                    "Response for {} is not valid JSON. Storing raw text.",
-                                    batch_names[i]
-                                );
-                                json!(content)
-                            }
-                        }
-                    } else {
-                        content.clone()
-                    };
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-                    let out_fname = Path::new(output_dir)
-                        .join(format!("{}{}.json", batch_names[i], file_suffix));
-                    let mut fo = File::create(&out_fname).expect("Failed to create output file");
+    #[test]
+    fn resolve_api_auth_passes_through_header_value() {
+        let cli = Some("Bearer xyz".to_string());
+        assert_eq!(
+            resolve_api_auth(cli.as_ref()),
+            Some("Bearer xyz".to_string())
+        );
+    }
 
-                    let json_val = serde_json::from_str(output_data.as_str().unwrap())
-                        .unwrap_or(output_data.clone());
+    #[test]
+    fn resolve_api_auth_wraps_bare_token() {
+        let cli = Some("sk-some-token".to_string());
+        assert_eq!(
+            resolve_api_auth(cli.as_ref()),
+            Some("Bearer sk-some-token".to_string())
+        );
+    }
 
-                    let json_str = if pretty_json {
-                        serde_json::to_string_pretty(&json_val).unwrap()
-                    } else {
-                        serde_json::to_string(&json_val).unwrap()
-                    };
-                    fo.write_all(json_str.as_bytes())
-                        .expect("Failed to write output file");
-                }
-            }
-            Err(e) => {
-                error!("Error processing batch: {}", e);
-            }
-        }
-        pb.inc(batch.len() as u64);
+    #[test]
+    fn resolve_api_auth_treats_blank_cli_value_as_unset() {
+        let cli = Some("   ".to_string());
+        assert_eq!(resolve_api_auth(cli.as_ref()), None);
     }
-    pb.finish_with_message("Done");
 }